@@ -1,15 +1,102 @@
+use async_trait::async_trait;
 use spin_sdk::http::{IntoResponse, Request, Response, Method};
 use spin_sdk::http_component;
 use std::collections::BTreeMap;
 
+// CORS policy, read from Spin component variables (based on the actix-cors
+// configuration-builder approach). Unset variables fall back to the old
+// wide-open behaviour so existing deployments keep working.
+#[derive(Debug, Clone)]
+struct CorsPolicy {
+    allowed_origins: Option<Vec<String>>,
+    allowed_methods: Vec<String>,
+    allowed_headers: Option<Vec<String>>,
+    expose_headers: Option<Vec<String>>,
+    max_age: String,
+    allow_credentials: bool,
+}
+
+impl CorsPolicy {
+    fn from_config() -> Self {
+        let allowed_origins = config_var("cors_allowed_origins")
+            .filter(|v| v != "*")
+            .map(|v| split_list(&v));
+
+        let allowed_methods = config_var("cors_allowed_methods")
+            .map(|v| split_list(&v))
+            .unwrap_or_else(|| {
+                split_list("GET, POST, PUT, DELETE, PATCH, OPTIONS, HEAD")
+            });
+
+        let allowed_headers = config_var("cors_allowed_headers")
+            .filter(|v| v != "*")
+            .map(|v| split_list(&v));
+
+        let expose_headers = config_var("cors_expose_headers")
+            .filter(|v| v != "*")
+            .map(|v| split_list(&v));
+
+        let max_age = config_var("cors_max_age").unwrap_or_else(|| "86400".to_string());
+
+        let allow_credentials = config_var("cors_allow_credentials")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+
+        Self {
+            allowed_origins,
+            allowed_methods,
+            allowed_headers,
+            expose_headers,
+            max_age,
+            allow_credentials,
+        }
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        match &self.allowed_origins {
+            None => true,
+            Some(origins) => origins.iter().any(|o| o == origin),
+        }
+    }
+}
+
+// Reads a Spin component variable, returning None if it is unset or empty.
+// `spin_sdk::variables::get` only resolves inside an actual Spin/wasm host,
+// so under `cfg(test)` this reads from the in-memory seam in `test_support`
+// instead (mirroring the `HttpRequester` trait used for the network call).
+#[cfg(not(test))]
+fn config_var(key: &str) -> Option<String> {
+    spin_sdk::variables::get(key)
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+fn config_var(key: &str) -> Option<String> {
+    test_support::config_var(key).filter(|v| !v.is_empty())
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 // Mock types (simplified versions from your m4p code)
 mod mock {
     use std::collections::BTreeMap;
     
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
     pub enum Method {
         GET,
         POST,
+        PUT,
+        DELETE,
+        PATCH,
+        HEAD,
+        OPTIONS,
     }
     
     #[derive(Debug, Clone)]
@@ -35,7 +122,7 @@ mod mock {
         pub body: Vec<u8>,
     }
     
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct HttpResponse {
         pub status: u16,
         pub headers: BTreeMap<String, String>,
@@ -45,26 +132,59 @@ mod mock {
 
 #[http_component]
 async fn handle_cors_proxy(req: Request) -> impl IntoResponse {
+    let policy = CorsPolicy::from_config();
+    let origin = header_str(&req, "origin").map(|s| s.to_string());
+
     let response = match req.method() {
-        Method::Options => handle_preflight(),
-        _ => match proxy_request(req).await {
+        Method::Options => handle_preflight(&req, &policy),
+        _ => match proxy_request(req, &SpinRequester).await {
             Ok(resp) => resp,
             Err(e) => error_response(&e),
         },
     };
-    
-    add_cors_headers(response)
+
+    add_cors_headers(response, origin.as_deref(), &policy)
 }
 
-async fn proxy_request(req: Request) -> Result<Response, String> {
-    let target_url = extract_target_url(&req)?;
-    
+// Distinguishes genuinely unsupported methods (405) from everything else
+// that goes wrong while proxying (500), so callers get a meaningful status.
+#[derive(Debug)]
+enum ProxyError {
+    BadRequest(String),
+    MethodNotAllowed(String),
+}
+
+impl ProxyError {
+    fn status(&self) -> u16 {
+        match self {
+            ProxyError::BadRequest(_) => 500,
+            ProxyError::MethodNotAllowed(_) => 405,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ProxyError::BadRequest(m) | ProxyError::MethodNotAllowed(m) => m,
+        }
+    }
+}
+
+fn header_str<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.header(name).and_then(|v| v.as_str())
+}
+
+async fn proxy_request(req: Request, requester: &dyn HttpRequester) -> Result<Response, ProxyError> {
+    let target_url = extract_target_url(&req).map_err(ProxyError::BadRequest)?;
+
     // Convert Spin request to mock request (like in m4p)
     let mock_req = convert_spin_to_mock_request(req, &target_url)?;
-    
+
     // Send using the mock request system (like in m4p)
-    let mock_resp = send_request(mock_req).await?;
-    
+    let mut mock_resp = send_request(mock_req, requester).await.map_err(ProxyError::BadRequest)?;
+    // Upstream CORS headers are dropped here, whatever requester was used, so
+    // only this proxy's own policy ever reaches the browser.
+    mock_resp.headers.retain(|k, _| !is_cors_header(k));
+
     // Convert mock response back to Spin response (like in m4p)
     Ok(convert_mock_to_spin_response(mock_resp))
 }
@@ -98,17 +218,188 @@ fn validate_url(url: String) -> Result<String, String> {
     if !url.starts_with("http://") && !url.starts_with("https://") {
         return Err("Target URL must start with http:// or https://".to_string());
     }
+    is_target_allowed(&url)?;
     Ok(url)
 }
 
+// SSRF guard: without this, an anonymous caller can use the proxy to reach
+// cloud metadata endpoints, localhost, or other internal services. Applied
+// to every hop (including redirects), since validate_url runs on each one.
+fn is_target_allowed(url: &str) -> Result<(), String> {
+    let authority = url_host(url).ok_or_else(|| format!("Invalid target URL: {}", url))?;
+    let (host, port) = split_host_port(&authority, url.starts_with("https://"));
+
+    if let Some(denylist) = config_var("target_host_denylist") {
+        if split_list(&denylist).iter().any(|h| h.eq_ignore_ascii_case(&host)) {
+            return Err(format!("Target host '{}' is denied", host));
+        }
+    }
+
+    if let Some(allowlist) = config_var("target_host_allowlist") {
+        if !split_list(&allowlist).iter().any(|h| h.eq_ignore_ascii_case(&host)) {
+            return Err(format!("Target host '{}' is not in the allowlist", host));
+        }
+        // An explicit allowlist entry is the operator opting in deliberately;
+        // skip the generic IP-range checks below.
+        return check_port(port);
+    }
+
+    if host.eq_ignore_ascii_case("localhost") {
+        return Err("Target host 'localhost' is not allowed".to_string());
+    }
+
+    // Canonical `IpAddr::parse` only accepts dotted-decimal/hex-colon forms.
+    // A WHATWG-URL-spec-compliant outbound stack (which wasi-http ultimately
+    // sits on) will still resolve decimal/octal/hex single-integer literals
+    // like "2130706433" as 127.0.0.1, so fall back to that parse too.
+    let ip = host
+        .parse::<std::net::IpAddr>()
+        .ok()
+        .or_else(|| parse_ipv4_literal(&host).map(std::net::IpAddr::V4));
+    if let Some(ip) = ip {
+        if is_disallowed_ip(&ip) {
+            return Err(format!("Target address '{}' is not allowed", ip));
+        }
+    }
+
+    check_port(port)
+}
+
+// Parses the WHATWG URL "IPv4 number" forms: up to 4 dot-separated parts,
+// each decimal/octal (leading `0`)/hex (leading `0x`), where every part but
+// the last is a single byte and the last absorbs the rest of the 32 bits
+// (so a bare "2130706433" is accepted as a whole 32-bit value). Returns
+// None for anything that isn't actually numeric, so it never shadows a
+// normal hostname.
+fn parse_ipv4_literal(host: &str) -> Option<std::net::Ipv4Addr> {
+    let parts: Vec<&str> = host.split('.').collect();
+    if parts.is_empty() || parts.len() > 4 || parts.iter().any(|p| p.is_empty()) {
+        return None;
+    }
+
+    let mut numbers = Vec::with_capacity(parts.len());
+    for part in &parts {
+        numbers.push(parse_ipv4_number(part)?);
+    }
+
+    let total = numbers.len();
+    let last = numbers.pop()?;
+    if numbers.iter().any(|&n| n > 255) {
+        return None;
+    }
+
+    let remaining_bits = 32 - 8 * (total as u32 - 1);
+    if (last >> remaining_bits) != 0 {
+        return None;
+    }
+
+    let mut value = last;
+    for (i, n) in numbers.iter().enumerate() {
+        value += n << (8 * (total - 1 - i));
+    }
+    u32::try_from(value).ok().map(std::net::Ipv4Addr::from)
+}
+
+fn parse_ipv4_number(part: &str) -> Option<u64> {
+    if let Some(hex) = part.strip_prefix("0x").or_else(|| part.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    if part.len() > 1 && part.starts_with('0') {
+        return u64::from_str_radix(&part[1..], 8).ok();
+    }
+    part.parse::<u64>().ok()
+}
+
+fn check_port(port: u16) -> Result<(), String> {
+    let allowed_ports = config_var("target_allowed_ports")
+        .map(|v| {
+            split_list(&v)
+                .iter()
+                .filter_map(|p| p.parse::<u16>().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(|| vec![80, 443]);
+
+    if !allowed_ports.contains(&port) {
+        return Err(format!("Target port {} is not allowed", port));
+    }
+    Ok(())
+}
+
+fn split_host_port(authority: &str, is_https: bool) -> (String, u16) {
+    let default_port = if is_https { 443 } else { 80 };
+
+    // Strip a `user[:pass]@` prefix before computing host/port: otherwise an
+    // authority like "attacker@169.254.169.254" hides the real target host
+    // behind opaque userinfo and sails past every check below — the classic
+    // open-proxy SSRF this guard exists to close.
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+
+    if let Some(rest) = authority.strip_prefix('[') {
+        // Bracketed IPv6 literal, e.g. "[::1]:8080".
+        if let Some((host, after)) = rest.split_once(']') {
+            let port = after
+                .strip_prefix(':')
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(default_port);
+            return (host.to_string(), port);
+        }
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, port_str)) if !port_str.is_empty() && port_str.bytes().all(|b| b.is_ascii_digit()) => {
+            (host.to_string(), port_str.parse().unwrap_or(default_port))
+        }
+        _ => (authority.to_string(), default_port),
+    }
+}
+
+fn is_disallowed_ip(ip: &std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(v4) => {
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+        }
+        std::net::IpAddr::V6(v6) => {
+            // IPv4-mapped literals (e.g. "::ffff:169.254.169.254") must be
+            // judged by the same rules as their unwrapped v4 form, or they
+            // sail straight through the checks below. Deliberately uses the
+            // strict `to_ipv4_mapped` (::ffff:0:0/96) rather than the looser
+            // `to_ipv4`, which would also reinterpret a genuine v6 loopback
+            // like "::1" as the v4 address "0.0.0.1".
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_disallowed_ip(&std::net::IpAddr::V4(v4));
+            }
+
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || (v6.segments()[0] & 0xfe00) == 0xfc00 // unique local, fc00::/7
+                || (v6.segments()[0] & 0xffc0) == 0xfe80 // link-local, fe80::/10
+        }
+    }
+}
+
 // Convert Spin request to mock request (based on m4p pattern)
-fn convert_spin_to_mock_request(req: Request, target_url: &str) -> Result<mock::HttpRequest, String> {
+fn convert_spin_to_mock_request(req: Request, target_url: &str) -> Result<mock::HttpRequest, ProxyError> {
     let method = match req.method() {
         Method::Get => mock::Method::GET,
         Method::Post => mock::Method::POST,
-        _ => mock::Method::GET,
+        Method::Put => mock::Method::PUT,
+        Method::Delete => mock::Method::DELETE,
+        Method::Patch => mock::Method::PATCH,
+        Method::Head => mock::Method::HEAD,
+        Method::Options => mock::Method::OPTIONS,
+        other => {
+            return Err(ProxyError::MethodNotAllowed(format!(
+                "Unsupported HTTP method: {:?}",
+                other
+            )))
+        }
     };
-    
+
     let mut headers = BTreeMap::new();
     for (name, value) in req.headers() {
         if !is_hop_by_hop_header(name) {
@@ -126,19 +417,124 @@ fn convert_spin_to_mock_request(req: Request, target_url: &str) -> Result<mock::
     })
 }
 
-// Send request using mock system (based on m4p pattern)
-async fn send_request(mut r: mock::HttpRequest) -> Result<mock::HttpResponse, String> {
+// Send request using mock system (based on m4p pattern). Follows redirects
+// itself since the browser can't follow a cross-origin Location under CORS.
+async fn send_request(
+    mut r: mock::HttpRequest,
+    requester: &dyn HttpRequester,
+) -> Result<mock::HttpResponse, String> {
     r.headers.insert(
         "User-Agent".to_string(),
         "cors-proxy/1.0-spin".to_string(),
     );
     r.headers.insert("Accept".to_string(), "*/*".to_string());
-    
-    let spin_req = convert_mock_to_spin_request(r);
-    let spin_resp = spin_sdk::http::send(spin_req).await
-        .map_err(|e| e.to_string())?;
-    
-    convert_spin_response_to_mock(spin_resp)
+    // CORS request headers are addressed to this proxy, not the upstream.
+    r.headers.retain(|k, _| !is_cors_header(k));
+
+    let max_redirects = config_var("max_redirects")
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(10);
+
+    let mut visited = std::collections::HashSet::new();
+    let mut hsts_hosts = std::collections::HashSet::new();
+    let mut hops = 0u32;
+    let mut current = r;
+
+    loop {
+        let url = current.uri.to_string();
+        if !visited.insert(url.clone()) {
+            return Err(format!("Redirect loop detected at {}", url));
+        }
+
+        let mock_resp = requester.send(current.clone()).await?;
+
+        if let Some(host) = url_host(&url) {
+            if header_value(&mock_resp.headers, "strict-transport-security").is_some() {
+                hsts_hosts.insert(host);
+            }
+        }
+
+        if !is_redirect_status(mock_resp.status) {
+            return Ok(mock_resp);
+        }
+
+        hops += 1;
+        if hops > max_redirects {
+            return Err(format!("Too many redirects (max {})", max_redirects));
+        }
+
+        let location = header_value(&mock_resp.headers, "location")
+            .ok_or_else(|| "Redirect response missing Location header".to_string())?
+            .to_string();
+
+        let mut next_url = resolve_url(&url, &location)?;
+        if let Some(host) = url_host(&next_url) {
+            if hsts_hosts.contains(&host) {
+                if let Some(rest) = next_url.strip_prefix("http://") {
+                    next_url = format!("https://{}", rest);
+                }
+            }
+        }
+        let next_url = validate_url(next_url)?;
+
+        if matches!(mock_resp.status, 303)
+            || (matches!(mock_resp.status, 301 | 302) && matches!(current.method, mock::Method::POST))
+        {
+            current.method = mock::Method::GET;
+            current.body = Vec::new();
+        }
+        // 307/308 (and GETs redirected by 301/302) preserve method and body.
+
+        current.uri = mock::Uri::new(next_url);
+    }
+}
+
+fn is_redirect_status(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+fn header_value<'a>(headers: &'a BTreeMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+// Resolves a (possibly relative) Location header against the URL it was
+// returned for, without pulling in a full URL-parsing crate.
+fn resolve_url(base: &str, location: &str) -> Result<String, String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Ok(location.to_string());
+    }
+
+    let (scheme, rest) = base
+        .split_once("://")
+        .ok_or_else(|| format!("Invalid base URL: {}", base))?;
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let authority = &rest[..authority_end];
+
+    if let Some(stripped) = location.strip_prefix("//") {
+        return Ok(format!("{}://{}", scheme, stripped));
+    }
+
+    if let Some(stripped) = location.strip_prefix('/') {
+        return Ok(format!("{}://{}/{}", scheme, authority, stripped));
+    }
+
+    let path = &rest[authority_end..];
+    let dir = match path.rfind('/') {
+        Some(idx) => &path[..=idx],
+        None => "/",
+    };
+    Ok(format!("{}://{}{}{}", scheme, authority, dir, location))
+}
+
+fn url_host(url: &str) -> Option<String> {
+    let rest = url.split_once("://")?.1;
+    let authority_end = rest
+        .find(|c| c == '/' || c == '?' || c == '#')
+        .unwrap_or(rest.len());
+    Some(rest[..authority_end].to_string())
 }
 
 // Convert mock request to Spin request (based on m4p pattern)
@@ -146,6 +542,11 @@ fn convert_mock_to_spin_request(r: mock::HttpRequest) -> spin_sdk::http::Request
     let method = match r.method {
         mock::Method::GET => spin_sdk::http::Method::Get,
         mock::Method::POST => spin_sdk::http::Method::Post,
+        mock::Method::PUT => spin_sdk::http::Method::Put,
+        mock::Method::DELETE => spin_sdk::http::Method::Delete,
+        mock::Method::PATCH => spin_sdk::http::Method::Patch,
+        mock::Method::HEAD => spin_sdk::http::Method::Head,
+        mock::Method::OPTIONS => spin_sdk::http::Method::Options,
     };
 
     let mut req = spin_sdk::http::Request::new(method, r.uri.to_string());
@@ -171,11 +572,96 @@ fn convert_spin_response_to_mock(r: spin_sdk::http::Response) -> Result<mock::Ht
 
     let body = r.into_body();
 
-    Ok(mock::HttpResponse {
+    if body.len() > max_body_bytes() {
+        return Err(format!(
+            "Upstream response body ({} bytes) exceeds max_body_bytes ({})",
+            body.len(),
+            max_body_bytes()
+        ));
+    }
+
+    Ok(decode_if_compressed(mock::HttpResponse {
         status,
         headers,
         body,
-    })
+    }))
+}
+
+// Shared cap for both the raw upstream body (above) and the decompressed
+// body (in `decode_if_compressed`) — without the second check, a body just
+// under the cap can still unzip into an effectively unbounded decompression
+// bomb.
+fn max_body_bytes() -> usize {
+    config_var("max_body_bytes")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(10 * 1024 * 1024)
+}
+
+// Transparently decodes gzip/deflate/br response bodies so a caller that
+// didn't negotiate the encoding still gets a usable body. Gated behind a
+// config var so plain pass-through mode stays available; if decoding fails
+// for any reason, the original (still-encoded) bytes are returned untouched.
+fn decode_if_compressed(mut resp: mock::HttpResponse) -> mock::HttpResponse {
+    let decoding_enabled = config_var("decode_compressed_responses")
+        .map(|v| v != "false")
+        .unwrap_or(true);
+    if !decoding_enabled {
+        return resp;
+    }
+
+    let encoding = match header_value(&resp.headers, "content-encoding") {
+        Some(enc) => enc.to_lowercase(),
+        None => return resp,
+    };
+
+    let decoded = match encoding.as_str() {
+        "gzip" => decode_gzip(&resp.body),
+        "deflate" => decode_deflate(&resp.body),
+        "br" => decode_brotli(&resp.body),
+        _ => None,
+    };
+
+    // A decoded body that blows through the body-size cap is treated the
+    // same as a decode failure: fall back to the original (still-encoded,
+    // within-cap) bytes rather than handing back an unbounded payload.
+    if let Some(decoded) = decoded.filter(|d| d.len() <= max_body_bytes()) {
+        remove_header(&mut resp.headers, "content-encoding");
+        remove_header(&mut resp.headers, "content-length");
+        resp.headers
+            .insert("Content-Length".to_string(), decoded.len().to_string());
+        resp.body = decoded;
+    }
+
+    resp
+}
+
+fn decode_gzip(body: &[u8]) -> Option<Vec<u8>> {
+    use std::io::Read;
+    let mut out = Vec::new();
+    flate2::read::GzDecoder::new(body).read_to_end(&mut out).ok()?;
+    Some(out)
+}
+
+fn decode_deflate(body: &[u8]) -> Option<Vec<u8>> {
+    use std::io::Read;
+    let mut out = Vec::new();
+    flate2::read::DeflateDecoder::new(body).read_to_end(&mut out).ok()?;
+    Some(out)
+}
+
+fn decode_brotli(body: &[u8]) -> Option<Vec<u8>> {
+    use std::io::Read;
+    let mut out = Vec::new();
+    brotli::Decompressor::new(body, 4096)
+        .read_to_end(&mut out)
+        .ok()?;
+    Some(out)
+}
+
+fn remove_header(headers: &mut BTreeMap<String, String>, name: &str) {
+    if let Some(key) = headers.keys().find(|k| k.eq_ignore_ascii_case(name)).cloned() {
+        headers.remove(&key);
+    }
 }
 
 // Convert mock response to Spin response (based on m4p pattern)
@@ -191,22 +677,85 @@ fn convert_mock_to_spin_response(resp: mock::HttpResponse) -> Response {
     add_no_cache_headers(response)
 }
 
-fn handle_preflight() -> Response {
+fn handle_preflight(req: &Request, policy: &CorsPolicy) -> Response {
+    // Reflect what the browser actually asked for rather than returning a
+    // fixed list, so a locked-down allowlist doesn't break legitimate
+    // preflights for methods/headers we do support.
+    let requested_method = header_str(req, "access-control-request-method");
+    let allow_methods = match requested_method {
+        Some(m) if policy.allowed_methods.iter().any(|a| a.eq_ignore_ascii_case(m)) => {
+            m.to_string()
+        }
+        _ => policy.allowed_methods.join(", "),
+    };
+
+    let allow_headers = match (&policy.allowed_headers, header_str(req, "access-control-request-headers")) {
+        (None, Some(requested)) => requested.to_string(),
+        (None, None) => "*".to_string(),
+        (Some(allowed), _) => allowed.join(", "),
+    };
+
     Response::builder()
-    .status(200)
-    .header("Access-Control-Max-Age", "86400")
-    .build()
+        .status(200)
+        .header("Access-Control-Max-Age", policy.max_age.as_str())
+        .header("Access-Control-Allow-Methods", allow_methods)
+        .header("Access-Control-Allow-Headers", allow_headers)
+        .build()
 }
 
-fn error_response(error: &str) -> Response {
-    Response::new(500, format!("Proxy Error: {}", error))
+fn error_response(error: &ProxyError) -> Response {
+    Response::new(error.status(), format!("Proxy Error: {}", error.message()))
 }
 
-fn add_cors_headers(mut response: Response) -> Response {    
-    response.set_header("Access-Control-Allow-Origin", "*");
-    response.set_header("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, PATCH, OPTIONS, HEAD");
-    response.set_header("Access-Control-Allow-Headers", "*");
-    response.set_header("Access-Control-Expose-Headers", "*");
+fn add_cors_headers(mut response: Response, origin: Option<&str>, policy: &CorsPolicy) -> Response {
+    let origin_matched = match (&policy.allowed_origins, origin) {
+        (Some(_), Some(origin)) if policy.origin_allowed(origin) => {
+            response.set_header("Access-Control-Allow-Origin", origin);
+            response.set_header("Vary", "Origin");
+            true
+        }
+        (Some(_), _) => {
+            // No match (or no Origin header) against a configured allowlist:
+            // omit Access-Control-Allow-Origin entirely rather than widen it.
+            false
+        }
+        (None, _) => {
+            response.set_header("Access-Control-Allow-Origin", "*");
+            false
+        }
+    };
+
+    // Allow-Credentials must never be paired with a wildcard Allow-Origin;
+    // browsers reject that combination outright. Only send it when the
+    // request's origin actually matched a configured allowlist entry, i.e.
+    // exactly when Allow-Origin was set to that origin above (mirroring
+    // actix-cors here too).
+    if policy.allow_credentials && origin_matched {
+        response.set_header("Access-Control-Allow-Credentials", "true");
+    }
+
+    // A preflight response already carries the method/headers reflected by
+    // `handle_preflight`; keep those instead of overwriting them here.
+    let has_header = |response: &Response, name: &str| {
+        response.headers().any(|(k, _)| k.eq_ignore_ascii_case(name))
+    };
+
+    if !has_header(&response, "Access-Control-Allow-Methods") {
+        response.set_header("Access-Control-Allow-Methods", policy.allowed_methods.join(", "));
+    }
+
+    if !has_header(&response, "Access-Control-Allow-Headers") {
+        match &policy.allowed_headers {
+            Some(headers) => response.set_header("Access-Control-Allow-Headers", headers.join(", ")),
+            None => response.set_header("Access-Control-Allow-Headers", "*"),
+        }
+    }
+
+    match &policy.expose_headers {
+        Some(headers) => response.set_header("Access-Control-Expose-Headers", headers.join(", ")),
+        None => response.set_header("Access-Control-Expose-Headers", "*"),
+    }
+
     response
 }
 
@@ -228,3 +777,434 @@ fn is_cors_header(name: &str) -> bool {
     let name_lower = name.to_lowercase();
     name_lower.starts_with("access-control-") || name_lower == "vary"
 }
+
+// Abstracts the actual upstream call (based on servo's testable net load
+// refactor and conduit-test's `MockRequest`) so `proxy_request`/`send_request`
+// can be exercised in tests without a live network.
+#[async_trait(?Send)]
+trait HttpRequester {
+    async fn send(&self, req: mock::HttpRequest) -> Result<mock::HttpResponse, String>;
+}
+
+// Wraps the real `spin_sdk::http::send` call used in production.
+struct SpinRequester;
+
+#[async_trait(?Send)]
+impl HttpRequester for SpinRequester {
+    async fn send(&self, r: mock::HttpRequest) -> Result<mock::HttpResponse, String> {
+        let spin_req = convert_mock_to_spin_request(r);
+        let spin_resp = spin_sdk::http::send(spin_req).await
+            .map_err(|e| e.to_string())?;
+        convert_spin_response_to_mock(spin_resp)
+    }
+}
+
+#[cfg(test)]
+mod test_support {
+    use super::mock;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    // In-memory stand-in for Spin component variables, scoped per test thread
+    // so `config_var` never reaches the real `spin_sdk::variables::get` wit
+    // import (which panics outside an actual Spin/wasm host).
+    thread_local! {
+        static CONFIG: RefCell<BTreeMap<String, String>> = RefCell::new(BTreeMap::new());
+    }
+
+    pub fn config_var(key: &str) -> Option<String> {
+        CONFIG.with(|c| c.borrow().get(key).cloned())
+    }
+
+    // Canned upstream responses keyed by URL, plus a log of every request
+    // that was actually sent through it, analogous to conduit-test's
+    // `MockRequest`.
+    #[derive(Default)]
+    pub struct MockRequester {
+        responses: RefCell<BTreeMap<String, mock::HttpResponse>>,
+        sent: RefCell<Vec<mock::HttpRequest>>,
+    }
+
+    impl MockRequester {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn with_response(self, url: impl Into<String>, response: mock::HttpResponse) -> Self {
+            self.responses.borrow_mut().insert(url.into(), response);
+            self
+        }
+
+        pub fn sent_requests(&self) -> Vec<mock::HttpRequest> {
+            self.sent.borrow().clone()
+        }
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl super::HttpRequester for MockRequester {
+        async fn send(&self, req: mock::HttpRequest) -> Result<mock::HttpResponse, String> {
+            let url = req.uri.to_string();
+            self.sent.borrow_mut().push(req);
+            self.responses
+                .borrow()
+                .get(&url)
+                .cloned()
+                .ok_or_else(|| format!("no mocked response for {}", url))
+        }
+    }
+
+    pub fn mock_response(status: u16, headers: &[(&str, &str)], body: &[u8]) -> mock::HttpResponse {
+        let mut map = BTreeMap::new();
+        for (k, v) in headers {
+            map.insert(k.to_string(), v.to_string());
+        }
+        mock::HttpResponse {
+            status,
+            headers: map,
+            body: body.to_vec(),
+        }
+    }
+
+    // Builder for incoming Spin requests, analogous to actix-web's
+    // `TestRequest`, so tests can drive `proxy_request`/`extract_target_url`
+    // without constructing `spin_sdk::http::Request` by hand each time.
+    pub struct TestRequest {
+        method: spin_sdk::http::Method,
+        uri: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl TestRequest {
+        pub fn get(uri: impl Into<String>) -> Self {
+            Self {
+                method: spin_sdk::http::Method::Get,
+                uri: uri.into(),
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        pub fn post(uri: impl Into<String>) -> Self {
+            Self {
+                method: spin_sdk::http::Method::Post,
+                uri: uri.into(),
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        pub fn put(uri: impl Into<String>) -> Self {
+            Self {
+                method: spin_sdk::http::Method::Put,
+                uri: uri.into(),
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        pub fn delete(uri: impl Into<String>) -> Self {
+            Self {
+                method: spin_sdk::http::Method::Delete,
+                uri: uri.into(),
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        pub fn patch(uri: impl Into<String>) -> Self {
+            Self {
+                method: spin_sdk::http::Method::Patch,
+                uri: uri.into(),
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        pub fn head(uri: impl Into<String>) -> Self {
+            Self {
+                method: spin_sdk::http::Method::Head,
+                uri: uri.into(),
+                headers: Vec::new(),
+                body: Vec::new(),
+            }
+        }
+
+        pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+            self.headers.push((name.into(), value.into()));
+            self
+        }
+
+        pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+            self.body = body.into();
+            self
+        }
+
+        pub fn to_request(self) -> spin_sdk::http::Request {
+            let mut req = spin_sdk::http::Request::new(self.method, self.uri);
+            for (k, v) in self.headers {
+                req.set_header(k, v);
+            }
+            *req.body_mut() = self.body;
+            req
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::{mock_response, MockRequester, TestRequest};
+    use super::*;
+
+    #[tokio::test]
+    async fn x_target_url_header_takes_precedence_over_query_param() {
+        let req = TestRequest::get("/?url=http%3A%2F%2Ffallback.example")
+            .header("x-target-url", "http://primary.example")
+            .to_request();
+
+        let requester = MockRequester::new()
+            .with_response("http://primary.example", mock_response(200, &[], b"ok"));
+
+        let resp = proxy_request(req, &requester).await.unwrap();
+        assert_eq!(*resp.status(), 200);
+        assert_eq!(requester.sent_requests()[0].uri.to_string(), "http://primary.example");
+    }
+
+    #[tokio::test]
+    async fn query_param_url_is_used_when_no_header_is_present() {
+        let req = TestRequest::get("/?url=http%3A%2F%2Ffallback.example").to_request();
+
+        let requester = MockRequester::new()
+            .with_response("http://fallback.example", mock_response(200, &[], b"ok"));
+
+        proxy_request(req, &requester).await.unwrap();
+        assert_eq!(
+            requester.sent_requests()[0].uri.to_string(),
+            "http://fallback.example"
+        );
+    }
+
+    #[tokio::test]
+    async fn hop_by_hop_and_cors_request_headers_are_stripped_and_defaults_injected() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://api.example")
+            .header("connection", "keep-alive")
+            .header("access-control-request-method", "GET")
+            .to_request();
+
+        let requester = MockRequester::new()
+            .with_response("http://api.example", mock_response(200, &[], b"ok"));
+
+        proxy_request(req, &requester).await.unwrap();
+
+        let sent = &requester.sent_requests()[0];
+        assert!(!sent.headers.contains_key("connection"));
+        assert!(!sent.headers.contains_key("access-control-request-method"));
+        assert_eq!(sent.headers.get("User-Agent").unwrap(), "cors-proxy/1.0-spin");
+        assert_eq!(sent.headers.get("Accept").unwrap(), "*/*");
+    }
+
+    #[tokio::test]
+    async fn redirect_is_followed_to_the_final_response() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://start.example/")
+            .to_request();
+
+        let requester = MockRequester::new()
+            .with_response(
+                "http://start.example/",
+                mock_response(302, &[("location", "http://end.example/")], b""),
+            )
+            .with_response("http://end.example/", mock_response(200, &[], b"final"));
+
+        let resp = proxy_request(req, &requester).await.unwrap();
+        assert_eq!(*resp.status(), 200);
+        assert_eq!(requester.sent_requests().len(), 2);
+        assert_eq!(
+            requester.sent_requests()[1].uri.to_string(),
+            "http://end.example/"
+        );
+    }
+
+    #[tokio::test]
+    async fn redirect_loop_is_rejected() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://loop.example/")
+            .to_request();
+
+        let requester = MockRequester::new().with_response(
+            "http://loop.example/",
+            mock_response(302, &[("location", "http://loop.example/")], b""),
+        );
+
+        let err = proxy_request(req, &requester).await.unwrap_err();
+        assert!(err.message().contains("Redirect loop"), "{}", err.message());
+    }
+
+    #[tokio::test]
+    async fn hsts_header_upgrades_the_next_redirect_to_https() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://upgrade.example/")
+            .to_request();
+
+        let requester = MockRequester::new()
+            .with_response(
+                "http://upgrade.example/",
+                mock_response(
+                    301,
+                    &[
+                        ("location", "http://upgrade.example/next"),
+                        ("strict-transport-security", "max-age=31536000"),
+                    ],
+                    b"",
+                ),
+            )
+            .with_response("https://upgrade.example/next", mock_response(200, &[], b"ok"));
+
+        let resp = proxy_request(req, &requester).await.unwrap();
+        assert_eq!(*resp.status(), 200);
+        assert_eq!(
+            requester.sent_requests()[1].uri.to_string(),
+            "https://upgrade.example/next"
+        );
+    }
+
+    #[test]
+    fn gzip_deflate_and_br_responses_are_decoded_transparently() {
+        use std::io::Write;
+
+        let plain = b"hello from upstream".to_vec();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&plain).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut deflate =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        deflate.write_all(&plain).unwrap();
+        let deflate = deflate.finish().unwrap();
+
+        let mut br = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut br, 4096, 5, 22);
+            encoder.write_all(&plain).unwrap();
+        }
+
+        for (encoding, body) in [("gzip", gzip), ("deflate", deflate), ("br", br)] {
+            let resp = mock_response(200, &[("content-encoding", encoding)], &body);
+            let decoded = decode_if_compressed(resp);
+
+            assert_eq!(decoded.body, plain, "encoding {encoding}");
+            assert!(
+                header_value(&decoded.headers, "content-encoding").is_none(),
+                "encoding {encoding}"
+            );
+            assert_eq!(
+                header_value(&decoded.headers, "content-length"),
+                Some(plain.len().to_string().as_str()),
+                "encoding {encoding}"
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_decoded_body_falls_back_to_the_original_encoded_bytes() {
+        use std::io::Write;
+
+        let plain = vec![0u8; max_body_bytes() + 1];
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&plain).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let resp = mock_response(200, &[("content-encoding", "gzip")], &gzip);
+        let decoded = decode_if_compressed(resp);
+
+        assert_eq!(decoded.body, gzip);
+        assert_eq!(header_value(&decoded.headers, "content-encoding"), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn method_and_body_are_passed_through_unchanged_for_every_verb() {
+        let cases: [(&str, mock::Method); 4] = [
+            ("PUT", mock::Method::PUT),
+            ("DELETE", mock::Method::DELETE),
+            ("PATCH", mock::Method::PATCH),
+            ("HEAD", mock::Method::HEAD),
+        ];
+
+        for (name, expected) in cases {
+            let builder = match name {
+                "PUT" => TestRequest::put("/").body(b"payload".to_vec()),
+                "DELETE" => TestRequest::delete("/").body(b"payload".to_vec()),
+                "PATCH" => TestRequest::patch("/").body(b"payload".to_vec()),
+                "HEAD" => TestRequest::head("/"),
+                _ => unreachable!(),
+            };
+            let req = builder
+                .header("x-target-url", "http://api.example")
+                .to_request();
+
+            let requester = MockRequester::new()
+                .with_response("http://api.example", mock_response(200, &[], b"ok"));
+
+            proxy_request(req, &requester).await.unwrap();
+
+            let sent = &requester.sent_requests()[0];
+            assert_eq!(sent.method, expected, "method mismatch for {name}");
+            if name != "HEAD" {
+                assert_eq!(sent.body, b"payload", "body mismatch for {name}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn loopback_ip_target_is_rejected() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://127.0.0.1/")
+            .to_request();
+
+        let err = proxy_request(req, &MockRequester::new()).await.unwrap_err();
+        assert!(err.message().contains("not allowed"), "{}", err.message());
+    }
+
+    #[tokio::test]
+    async fn userinfo_prefixed_metadata_target_is_rejected() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://attacker@169.254.169.254/")
+            .to_request();
+
+        let err = proxy_request(req, &MockRequester::new()).await.unwrap_err();
+        assert!(err.message().contains("not allowed"), "{}", err.message());
+    }
+
+    #[tokio::test]
+    async fn decimal_ipv4_literal_loopback_target_is_rejected() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://2130706433/")
+            .to_request();
+
+        let err = proxy_request(req, &MockRequester::new()).await.unwrap_err();
+        assert!(err.message().contains("not allowed"), "{}", err.message());
+    }
+
+    #[tokio::test]
+    async fn upstream_cors_headers_are_stripped_from_the_proxied_response() {
+        let req = TestRequest::get("/")
+            .header("x-target-url", "http://api.example")
+            .to_request();
+
+        let requester = MockRequester::new().with_response(
+            "http://api.example",
+            mock_response(
+                200,
+                &[("access-control-allow-origin", "https://evil.example")],
+                b"ok",
+            ),
+        );
+
+        let resp = proxy_request(req, &requester).await.unwrap();
+        assert!(resp
+            .headers()
+            .all(|(k, _)| !k.eq_ignore_ascii_case("access-control-allow-origin")));
+    }
+}